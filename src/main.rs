@@ -1,21 +1,30 @@
-use failure::Error;
+use failure::{format_err, Error};
+use hashbrown::hash_map::RawEntryMut;
 use hashbrown::HashMap;
+use rayon::iter::{ParallelBridge, ParallelIterator};
 use rayon::slice::ParallelSliceMut;
 use signal_hook;
 use std::{
-    cmp::Ord,
+    cmp::{Ord, Ordering as CmpOrdering, Reverse},
+    collections::BinaryHeap,
     fs::File,
-    io::{stdin, stdout, BufRead, BufReader, Write},
+    io::{stdin, stdout, BufRead, BufReader, Read, Write},
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        mpsc, Arc,
     },
+    thread,
 };
 use structopt::{
     clap::{_clap_count_exprs, arg_enum},
     StructOpt,
 };
 
+/// Size of the buffers the reader thread fills before handing them off to
+/// the counting thread. Grown (doubled) on the fly if a single line is
+/// longer than this.
+const CHUNK_SIZE: usize = 128 * 1024;
+
 arg_enum! {
     #[derive(Debug)]
     enum SortingOrder {
@@ -39,28 +48,340 @@ struct Config {
     sort_by: SortingOrder,
     #[structopt(long = "top")]
     top: Option<usize>,
+    /// Caps how many shards are counted in parallel at once, defaulting
+    /// to the ambient rayon thread pool's size.
+    #[structopt(long = "jobs", short = "j")]
+    jobs: Option<usize>,
+    /// Treat each input as an already-produced `key\tcount` table (this
+    /// crate's own output format, sorted by key) and merge them instead of
+    /// counting raw lines.
+    #[structopt(long = "merge")]
+    merge: bool,
+    /// Use a stable sort so output ordering is byte-for-byte reproducible
+    /// across runs and thread counts even if a future feature introduces
+    /// non-unique keys.
+    #[structopt(long = "stable")]
+    stable: bool,
     #[structopt()]
-    input: Option<String>,
+    input: Vec<String>,
 }
 
-fn create_reader(input: &Option<String>) -> Result<Box<BufRead>, Error> {
-    let reader: Box<BufRead> = match input {
-        Some(file_name) => Box::new(BufReader::new(File::open(file_name)?)),
-        None => Box::new(BufReader::new(stdin())),
+/// Builds one combined reader out of `inputs`, read in order as if they
+/// were concatenated; falls back to stdin when none are given.
+fn create_reader(inputs: &[String]) -> Result<Box<dyn Read + Send>, Error> {
+    let mut paths = inputs.iter();
+    let mut reader: Box<dyn Read + Send> = match paths.next() {
+        Some(file_name) => Box::new(File::open(file_name)?),
+        None => return Ok(Box::new(stdin())),
     };
+    for file_name in paths {
+        reader = Box::new(reader.chain(File::open(file_name)?));
+    }
     Ok(reader)
 }
 
-fn sort_counts<T: Ord + Sync>(counts: &mut Vec<(&String, &T)>, sorting_order: &SortingOrder) {
-    match sorting_order {
-        SortingOrder::Key => {
-            counts.par_sort_unstable_by(|a, b| a.0.cmp(b.0).then(a.1.cmp(b.1).reverse()))
+/// Opens one reader per input for merge mode, where each source stays
+/// distinct rather than being chained into a single stream; falls back to
+/// a single stdin source when none are given.
+fn open_sources(inputs: &[String]) -> Result<Vec<Box<dyn BufRead>>, Error> {
+    if inputs.is_empty() {
+        return Ok(vec![Box::new(BufReader::new(stdin()))]);
+    }
+    inputs
+        .iter()
+        .map(|file_name| -> Result<Box<dyn BufRead>, Error> {
+            Ok(Box::new(BufReader::new(File::open(file_name)?)))
+        })
+        .collect()
+}
+
+/// Reads `reader` in fixed-size chunks and sends each chunk, truncated to
+/// the last newline it contains, over `tx`. The trailing bytes of a chunk
+/// that belong to a line straddling the boundary are carried over and
+/// prepended to the next read instead of being sent, so every chunk the
+/// counting thread receives can be split on `\n` on its own.
+fn read_chunks(mut reader: Box<dyn Read + Send>, tx: mpsc::SyncSender<Vec<u8>>) -> Result<(), Error> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut carry = 0usize;
+
+    loop {
+        if carry == buf.len() {
+            buf.resize(buf.len() * 2, 0);
+        }
+
+        let n = reader.read(&mut buf[carry..])?;
+        if n == 0 {
+            if carry > 0 {
+                let _ = tx.send(buf[..carry].to_vec());
+            }
+            return Ok(());
+        }
+
+        let filled = carry + n;
+        match buf[..filled].iter().rposition(|&b| b == b'\n') {
+            Some(pos) => {
+                let split_at = pos + 1;
+                if tx.send(buf[..split_at].to_vec()).is_err() {
+                    return Ok(());
+                }
+                buf.copy_within(split_at..filled, 0);
+                carry = filled - split_at;
+            }
+            None => carry = filled,
+        }
+    }
+}
+
+/// Counts the lines in `chunk` into `counter`, interning each distinct
+/// line's bytes only the first time it's seen. Repeated lines are looked
+/// up by the borrowed slice and only bump an integer, with no allocation.
+///
+/// Mirrors `BufRead::lines`' CRLF handling: a `\r` right before a line's
+/// `\n` is stripped so CRLF-terminated input counts the same way
+/// LF-terminated input does, while a final line with no `\n` is kept as is.
+fn count_chunk(chunk: &[u8], counter: &mut HashMap<Box<[u8]>, u64>) {
+    let (body, terminated) = match chunk.last() {
+        Some(b'\n') => (&chunk[..chunk.len() - 1], true),
+        _ => (chunk, false),
+    };
+
+    let mut lines = body.split(|&b| b == b'\n').peekable();
+    while let Some(mut line) = lines.next() {
+        let has_newline = terminated || lines.peek().is_some();
+        if has_newline && line.last() == Some(&b'\r') {
+            line = &line[..line.len() - 1];
+        }
+
+        match counter.raw_entry_mut().from_key(line) {
+            RawEntryMut::Occupied(mut entry) => *entry.get_mut() += 1,
+            RawEntryMut::Vacant(entry) => {
+                entry.insert(line.to_vec().into_boxed_slice(), 1);
+            }
+        }
+    }
+}
+
+/// Counts a single chunk into its own map. Each chunk handed out by
+/// `read_chunks` is already snapped to the next `\n`, so it's a
+/// self-contained shard that can be counted independently of its
+/// neighbours.
+fn count_shard(chunk: &[u8]) -> HashMap<Box<[u8]>, u64> {
+    let mut counter = HashMap::default();
+    count_chunk(chunk, &mut counter);
+    counter
+}
+
+/// Folds `b`'s counts into `a`, summing the counts of keys present in both.
+fn merge_counts(
+    mut a: HashMap<Box<[u8]>, u64>,
+    b: HashMap<Box<[u8]>, u64>,
+) -> HashMap<Box<[u8]>, u64> {
+    for (key, count) in b {
+        *a.entry(key).or_insert(0) += count;
+    }
+    a
+}
+
+/// Counts every chunk arriving on `rx` as its own shard in parallel with
+/// rayon, then reduces the per-shard maps into one by summing matching
+/// keys; the reduce is the only part of this that's serial per pair of
+/// maps. `jobs` caps how many shards are counted at once, defaulting to
+/// the ambient rayon thread pool's size when `None`.
+fn count_all(
+    rx: mpsc::Receiver<Vec<u8>>,
+    jobs: Option<usize>,
+) -> Result<HashMap<Box<[u8]>, u64>, Error> {
+    let reduce = || {
+        rx.into_iter()
+            .par_bridge()
+            .map(|chunk| count_shard(&chunk))
+            .reduce(HashMap::default, merge_counts)
+    };
+
+    match jobs {
+        Some(jobs) => {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs).build()?;
+            Ok(pool.install(reduce))
+        }
+        None => Ok(reduce()),
+    }
+}
+
+type MergeEntry = (Box<[u8]>, u64);
+
+/// A single `--merge` input: a `key\tcount` table, read one entry at a
+/// time rather than loaded fully into memory.
+struct MergeSource {
+    reader: Box<dyn BufRead>,
+    last_key: Option<Box<[u8]>>,
+}
+
+impl MergeSource {
+    fn new(reader: Box<dyn BufRead>) -> Self {
+        MergeSource {
+            reader,
+            last_key: None,
+        }
+    }
+
+    /// Reads and parses the next `key\tcount` line. The key is split off
+    /// at the last tab so keys containing embedded tabs round-trip.
+    ///
+    /// Errors if the key doesn't sort at or after the previous key read
+    /// from this source: `--merge` assumes every input is already sorted
+    /// by key (e.g. produced with `-s key`), and a k-way merge over an
+    /// out-of-order source would silently under-count keys that reappear
+    /// later instead of merging them.
+    fn next_entry(&mut self) -> Result<Option<MergeEntry>, Error> {
+        let mut line = Vec::new();
+        if self.reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        }
+
+        let tab = line
+            .iter()
+            .rposition(|&b| b == b'\t')
+            .ok_or_else(|| format_err!("malformed merge input line: {:?}", String::from_utf8_lossy(&line)))?;
+        let count = std::str::from_utf8(&line[tab + 1..])?.parse()?;
+        let key = line[..tab].to_vec().into_boxed_slice();
+
+        if let Some(last_key) = &self.last_key {
+            if key < *last_key {
+                return Err(format_err!(
+                    "merge input is not sorted by key: {:?} follows {:?} (produce merge inputs with `-s key`)",
+                    String::from_utf8_lossy(&key),
+                    String::from_utf8_lossy(last_key),
+                ));
+            }
+        }
+        self.last_key = Some(key.clone());
+
+        Ok(Some((key, count)))
+    }
+}
+
+/// Performs a streaming k-way merge of `sources`, each already sorted by
+/// key, summing counts for keys shared across sources. Only one pending
+/// entry per source is held in the heap at a time, so none of the input
+/// tables need to be loaded fully into memory.
+fn merge_sources(mut sources: Vec<MergeSource>) -> Result<HashMap<Box<[u8]>, u64>, Error> {
+    type HeapEntry = Reverse<(Box<[u8]>, u64, usize)>;
+
+    fn refill(sources: &mut [MergeSource], heap: &mut BinaryHeap<HeapEntry>, idx: usize) -> Result<(), Error> {
+        if let Some((key, count)) = sources[idx].next_entry()? {
+            heap.push(Reverse((key, count, idx)));
+        }
+        Ok(())
+    }
+
+    let mut heap: BinaryHeap<HeapEntry> = BinaryHeap::new();
+    for idx in 0..sources.len() {
+        refill(&mut sources, &mut heap, idx)?;
+    }
+
+    let mut merged: HashMap<Box<[u8]>, u64> = HashMap::default();
+    while let Some(Reverse((key, count, idx))) = heap.pop() {
+        let mut total = count;
+        refill(&mut sources, &mut heap, idx)?;
+
+        loop {
+            match heap.peek() {
+                Some(Reverse((top_key, _, _))) if top_key.as_ref() == key.as_ref() => {
+                    let Reverse((_, next_count, next_idx)) =
+                        heap.pop().expect("just matched via peek");
+                    total += next_count;
+                    refill(&mut sources, &mut heap, next_idx)?;
+                }
+                _ => break,
+            }
         }
-        SortingOrder::Count => {
-            counts.par_sort_unstable_by(|a, b| a.1.cmp(b.1).reverse().then(a.0.cmp(b.0)))
+
+        merged.insert(key, total);
+    }
+
+    Ok(merged)
+}
+
+type Entry<'a> = (&'a Box<[u8]>, &'a u64);
+
+// Keys are unique, so in both comparators below the key alone is enough to
+// break ties and give a total order: output stays byte-for-byte
+// reproducible across runs and thread counts regardless of `--stable`.
+
+fn cmp_by_key(a: &Entry<'_>, b: &Entry<'_>) -> CmpOrdering {
+    a.0.cmp(b.0)
+}
+
+fn cmp_by_count(a: &Entry<'_>, b: &Entry<'_>) -> CmpOrdering {
+    a.1.cmp(b.1).reverse().then(a.0.cmp(b.0))
+}
+
+fn sort_counts(counts: &mut Vec<Entry>, sorting_order: &SortingOrder, stable: bool) {
+    match (sorting_order, stable) {
+        (SortingOrder::Key, false) => counts.par_sort_unstable_by(cmp_by_key),
+        (SortingOrder::Key, true) => counts.par_sort_by(cmp_by_key),
+        (SortingOrder::Count, false) => counts.par_sort_unstable_by(cmp_by_count),
+        (SortingOrder::Count, true) => counts.par_sort_by(cmp_by_count),
+        (SortingOrder::None, _) => (),
+    }
+}
+
+/// Wraps an `Entry` so a `BinaryHeap` can order it by an arbitrary `cmp`
+/// function rather than its natural `Ord`. `cmp` is the same ascending
+/// comparator `sort_counts` would use, so the heap's root (a `BinaryHeap`'s
+/// maximum) is always the current worst of the entries being kept.
+struct RankedEntry<'a> {
+    entry: Entry<'a>,
+    cmp: fn(&Entry<'_>, &Entry<'_>) -> CmpOrdering,
+}
+
+impl<'a> PartialEq for RankedEntry<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.cmp)(&self.entry, &other.entry) == CmpOrdering::Equal
+    }
+}
+
+impl<'a> Eq for RankedEntry<'a> {}
+
+impl<'a> PartialOrd for RankedEntry<'a> {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a> Ord for RankedEntry<'a> {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (self.cmp)(&self.entry, &other.entry)
+    }
+}
+
+/// Keeps only the `n` entries that rank first under `cmp`, using a bounded
+/// min-heap instead of sorting every distinct key. Runs in O(M log N) time
+/// and O(N) memory instead of `sort_counts`'s O(M log M) full sort.
+fn select_top<'a>(
+    counter: &'a HashMap<Box<[u8]>, u64>,
+    n: usize,
+    cmp: fn(&Entry<'_>, &Entry<'_>) -> CmpOrdering,
+) -> Vec<Entry<'a>> {
+    let mut heap: BinaryHeap<RankedEntry<'a>> = BinaryHeap::with_capacity(n + 1);
+
+    for entry in counter.iter() {
+        if heap.len() < n {
+            heap.push(RankedEntry { entry, cmp });
+        } else if let Some(worst) = heap.peek() {
+            if cmp(&entry, &worst.entry) == CmpOrdering::Less {
+                heap.pop();
+                heap.push(RankedEntry { entry, cmp });
+            }
         }
-        SortingOrder::None => (),
     }
+
+    let mut top: Vec<Entry<'a>> = heap.into_iter().map(|ranked| ranked.entry).collect();
+    top.sort_unstable_by(cmp);
+    top
 }
 
 fn watch_sig_pipe() -> Result<Arc<AtomicBool>, Error> {
@@ -74,23 +395,44 @@ fn main() -> Result<(), Error> {
 
     let config = Config::from_args();
 
-    let reader = create_reader(&config.input)?;
+    let counter = if config.merge {
+        let sources = open_sources(&config.input)?
+            .into_iter()
+            .map(MergeSource::new)
+            .collect();
+        merge_sources(sources)?
+    } else {
+        let reader = create_reader(&config.input)?;
 
-    let mut counter: HashMap<_, u64> = Default::default();
+        let (tx, rx) = mpsc::sync_channel(4);
+        let reader_thread = thread::spawn(move || read_chunks(reader, tx));
 
-    for line in reader.lines() {
-        *counter.entry(line?).or_insert(0) += 1;
-    }
+        let counter = count_all(rx, config.jobs)?;
+
+        reader_thread
+            .join()
+            .map_err(|_| format_err!("reader thread panicked"))??;
 
-    let mut counts: Vec<_> = counter.iter().collect();
-    sort_counts(&mut counts, &config.sort_by);
+        counter
+    };
+
+    let counts: Vec<Entry> = match (&config.sort_by, config.top) {
+        (SortingOrder::Key, Some(n)) => select_top(&counter, n, cmp_by_key),
+        (SortingOrder::Count, Some(n)) => select_top(&counter, n, cmp_by_count),
+        _ => {
+            let mut counts: Vec<_> = counter.iter().collect();
+            sort_counts(&mut counts, &config.sort_by, config.stable);
+            counts
+        }
+    };
 
     let n = config.top.unwrap_or_else(|| counts.len());
 
     let stdout = stdout();
     let mut handle = stdout.lock();
     for (key, count) in counts.iter().take(n) {
-        writeln!(handle, "{}\t{}", key, count)?;
+        handle.write_all(key)?;
+        writeln!(handle, "\t{}", count)?;
         if sig_pipe.load(Ordering::Relaxed) {
             break;
         }
@@ -98,3 +440,155 @@ fn main() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod chunk_reading_tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn collect_chunks(data: &[u8]) -> Vec<Vec<u8>> {
+        let reader: Box<dyn Read + Send> = Box::new(Cursor::new(data.to_vec()));
+        let (tx, rx) = mpsc::sync_channel(4);
+        let handle = thread::spawn(move || read_chunks(reader, tx));
+        let chunks: Vec<Vec<u8>> = rx.into_iter().collect();
+        handle.join().unwrap().unwrap();
+        chunks
+    }
+
+    #[test]
+    fn chunks_reassemble_to_the_original_bytes() {
+        let data = b"a\nb\nc\n";
+        let chunks = collect_chunks(data);
+        assert_eq!(chunks.concat(), data);
+        for chunk in &chunks {
+            assert_eq!(chunk.last(), Some(&b'\n'));
+        }
+    }
+
+    #[test]
+    fn a_line_spanning_several_chunk_sized_reads_is_carried_over_intact() {
+        // Far bigger than CHUNK_SIZE, so the producer must grow (double)
+        // its buffer and carry the partial line across several reads
+        // before it finally sees the newline.
+        let long_line = vec![b'x'; CHUNK_SIZE * 3];
+        let mut data = long_line.clone();
+        data.push(b'\n');
+        data.extend_from_slice(b"tail\n");
+
+        let chunks = collect_chunks(&data);
+        let joined = chunks.concat();
+        assert_eq!(joined, data);
+
+        let mut lines = joined.split(|&b| b == b'\n');
+        assert_eq!(lines.next().unwrap(), long_line.as_slice());
+        assert_eq!(lines.next().unwrap(), b"tail");
+    }
+
+    #[test]
+    fn a_final_unterminated_line_is_still_emitted() {
+        let data = b"a\nb\nc";
+        let chunks = collect_chunks(data);
+        assert_eq!(chunks.concat(), data);
+        assert_eq!(chunks.last().unwrap().last(), Some(&b'c'));
+    }
+
+    #[test]
+    fn count_chunk_strips_a_trailing_cr_like_buf_read_lines() {
+        let mut counter: HashMap<Box<[u8]>, u64> = HashMap::default();
+        count_chunk(b"a\r\nb\r\na\r\n", &mut counter);
+
+        assert_eq!(counter.get(b"a".as_ref()).copied(), Some(2));
+        assert_eq!(counter.get(b"b".as_ref()).copied(), Some(1));
+        assert_eq!(counter.get(b"a\r".as_ref()), None);
+    }
+}
+
+#[cfg(test)]
+mod select_top_tests {
+    use super::*;
+
+    fn counter(entries: &[(&str, u64)]) -> HashMap<Box<[u8]>, u64> {
+        entries
+            .iter()
+            .map(|(k, v)| (k.as_bytes().to_vec().into_boxed_slice(), *v))
+            .collect()
+    }
+
+    fn keys<'a>(top: &'a [Entry<'a>]) -> Vec<&'a [u8]> {
+        top.iter().map(|(k, _)| k.as_ref()).collect()
+    }
+
+    #[test]
+    fn breaks_count_ties_by_the_lexicographically_smaller_key() {
+        let counter = counter(&[("c", 10), ("a", 5), ("b", 5), ("d", 1)]);
+
+        let top = select_top(&counter, 2, cmp_by_count);
+
+        // "c" has the highest count; "a" and "b" tie on count 5, so the
+        // smaller key wins the remaining slot over "b", and "d", with the
+        // lowest count, is left out.
+        assert_eq!(keys(&top), vec![b"c".as_ref(), b"a".as_ref()]);
+    }
+
+    #[test]
+    fn by_key_keeps_the_lexicographically_smallest_keys() {
+        let counter = counter(&[("c", 1), ("a", 1), ("b", 1), ("d", 1)]);
+
+        let top = select_top(&counter, 2, cmp_by_key);
+
+        assert_eq!(keys(&top), vec![b"a".as_ref(), b"b".as_ref()]);
+    }
+
+    #[test]
+    fn n_greater_than_the_number_of_entries_returns_them_all() {
+        let counter = counter(&[("a", 1), ("b", 2)]);
+
+        let top = select_top(&counter, 10, cmp_by_count);
+
+        assert_eq!(keys(&top), vec![b"b".as_ref(), b"a".as_ref()]);
+    }
+}
+
+#[cfg(test)]
+mod merge_tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn source(table: &str) -> MergeSource {
+        MergeSource::new(Box::new(Cursor::new(table.as_bytes().to_vec())))
+    }
+
+    #[test]
+    fn sums_counts_for_keys_shared_across_sources() {
+        let merged = merge_sources(vec![
+            source("apple\t1\nbanana\t2\n"),
+            source("apple\t2\ncherry\t1\n"),
+        ])
+        .unwrap();
+
+        assert_eq!(merged.get(b"apple".as_ref()).copied(), Some(3));
+        assert_eq!(merged.get(b"banana".as_ref()).copied(), Some(2));
+        assert_eq!(merged.get(b"cherry".as_ref()).copied(), Some(1));
+        assert_eq!(merged.len(), 3);
+    }
+
+    #[test]
+    fn accumulates_a_key_shared_by_more_than_two_sources() {
+        let merged = merge_sources(vec![
+            source("apple\t1\n"),
+            source("apple\t2\n"),
+            source("apple\t3\n"),
+        ])
+        .unwrap();
+
+        assert_eq!(merged.get(b"apple".as_ref()).copied(), Some(6));
+    }
+
+    #[test]
+    fn rejects_a_source_whose_keys_are_not_sorted() {
+        let mut unsorted = source("banana\t1\napple\t1\n");
+
+        assert!(unsorted.next_entry().unwrap().is_some());
+        assert!(unsorted.next_entry().is_err());
+    }
+}